@@ -3,21 +3,127 @@ extern crate glium;
 extern crate image;
 extern crate twoway;
 extern crate elementtree;
+extern crate exif;
 
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
 use glium::index::PrimitiveType;
+use glium::texture::RawImage2d;
+use glium::framebuffer::SimpleFrameBuffer;
 use glium::{glutin, Surface};
 use glium::uniforms::{ SamplerWrapFunction, MinifySamplerFilter, MagnifySamplerFilter };
 use glutin::{event_loop::ControlFlow, event::Event, event::WindowEvent, event::MouseScrollDelta, event::ElementState::{Pressed, Released}};
-use glutin::event::VirtualKeyCode::{Left, Right, Up, Down, PageUp, PageDown};
+use glutin::event::VirtualKeyCode::{Left, Right, Up, Down, PageUp, PageDown, L, S, T, LBracket, RBracket};
+
+/// Output projection modes, matching the `VIEW_*` constants in the fragment shader
+const VIEW_RECTILINEAR: i32 = 0;
+const VIEW_LITTLE_PLANET: i32 = 1;
+
+/// Tone-mapping operators, matching the `TONEMAP_*` constants in the fragment shader
+const TONEMAP_NONE: i32 = 0;
+const TONEMAP_REINHARD: i32 = 1;
+const TONEMAP_FILMIC: i32 = 2;
+const TONEMAP_MODE_COUNT: i32 = 3;
 
 mod metadata;
+mod tiles;
+
+/// Either a single GL texture holding the whole panorama, or a tiled +
+/// mipmapped pyramid for panoramas larger than `GL_MAX_TEXTURE_SIZE`.
+enum PanoTexture {
+    Single(glium::texture::SrgbTexture2d),
+    Tiled(tiles::TiledTexture),
+}
+
+impl PanoTexture {
+    fn new(display: &glium::Display, image: image::RgbaImage, max_texture_size: u32) -> PanoTexture {
+        let (w, h) = image.dimensions();
+        if w > max_texture_size || h > max_texture_size {
+            PanoTexture::Tiled(tiles::TiledTexture::new(image, max_texture_size, 32))
+        } else {
+            let gl_image = glium::texture::RawImage2d::from_raw_rgba_reversed(&image.into_raw(), (w, h));
+            PanoTexture::Single(glium::texture::SrgbTexture2d::new(display, gl_image).unwrap())
+        }
+    }
+
+    /// The tiles that need to be drawn to cover the whole panorama at the
+    /// level of detail appropriate for `zoom` and the given output width.
+    fn tiles_to_draw(&mut self, display: &glium::Display, zoom: f64, output_width: u32) -> Vec<tiles::Tile> {
+        match self {
+            PanoTexture::Single(tex) => vec![tiles::Tile { texture: tex, offset: [0.0, 0.0], size: [1.0, 1.0] }],
+            PanoTexture::Tiled(tiled) => {
+                let level = tiled.level_for_zoom(zoom, output_width);
+                tiled.tiles_for_level(display, level)
+            }
+        }
+    }
+}
+
+/// Render the current (or an arbitrary-resolution) view into an offscreen
+/// framebuffer and save it as a PNG, independent of the window size.
+fn export_view<V: Copy + glium::vertex::Vertex>(
+    display: &glium::Display,
+    program: &glium::Program,
+    vertex_buffer: &glium::VertexBuffer<V>,
+    index_buffer: &glium::IndexBuffer<u16>,
+    pano_texture: &mut PanoTexture,
+    meta: &metadata::PanoMeta,
+    yaw: f64, pitch: f64, roll: f64, zoom: f64, view_mode: i32,
+    exposure: f64, tonemap: i32,
+    width: u32, height: u32,
+    path: &str,
+) -> Result<(), String> {
+    let target_tex = glium::texture::SrgbTexture2d::empty(display, width, height)
+        .map_err(|e| format!("Failed to allocate export framebuffer: {:?}", e))?;
+    let mut framebuffer = SimpleFrameBuffer::new(display, &target_tex)
+        .map_err(|e| format!("Failed to create export framebuffer: {:?}", e))?;
+
+    framebuffer.clear_color(0.0, 0.0, 0.0, 0.0);
+
+    for tile in pano_texture.tiles_to_draw(display, zoom, width) {
+        let uniforms = uniform! {
+            window_aspect_ratio: height as f32 / width as f32,
+            yaw: yaw as f32,
+            pitch: pitch as f32,
+            roll: roll as f32,
+            zoom: zoom as f32,
+            view_mode: view_mode,
+            exposure: exposure as f32,
+            tonemap: tonemap,
+            projection: meta.projection.as_gl_int(),
+            image_offset: [ meta.crop_left, 1.0 - meta.crop_top - meta.height_ratio ],
+            image_fov: [ meta.width_ratio, meta.height_ratio ],
+            tile_offset: tile.offset,
+            tile_size: tile.size,
+            tex: tile.texture.sampled()
+                .wrap_function(SamplerWrapFunction::Clamp)
+                .minify_filter(MinifySamplerFilter::Linear)
+                .magnify_filter(MagnifySamplerFilter::Linear)
+        };
+
+        framebuffer
+            .draw(vertex_buffer, index_buffer, program, &uniforms, &Default::default())
+            .map_err(|e| format!("Failed to render export view: {:?}", e))?;
+    }
+
+    let raw: RawImage2d<u8> = target_tex.read();
+    let img = image::RgbaImage::from_raw(width, height, raw.data.into_owned())
+        .ok_or_else(|| format!("Failed to assemble exported image"))?;
+    image::imageops::flip_vertical(&img)
+        .save(path)
+        .map_err(|e| format!("Failed to write {}: {:?}", path, e))?;
+
+    println!("Exported {}x{} view to {}", width, height, path);
+    Ok(())
+}
 
 fn main() -> Result<(), String> {
     let args = env::args().collect::<Vec<_>>();
     let image_name = args.get(1).ok_or(format!("Missing argument"))?;
+    let export_width: u32 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(4096);
+    let export_height: u32 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(2048);
 
     let input_img = image::open(image_name).unwrap().to_rgba();
     let mut buf = Vec::new();
@@ -30,9 +136,8 @@ fn main() -> Result<(), String> {
     let context = glium::glutin::ContextBuilder::new();
     let display = glium::Display::new(window, context, &event_loop).unwrap();
 
-    let image_dimensions = input_img.dimensions();
-    let gl_image = glium::texture::RawImage2d::from_raw_rgba_reversed(&input_img.into_raw(), image_dimensions);
-    let opengl_texture = glium::texture::SrgbTexture2d::new(&display, gl_image).unwrap();
+    let max_texture_size = display.get_context().get_capabilities().max_texture_size as u32;
+    let mut pano_texture = PanoTexture::new(&display, input_img, max_texture_size);
 
     let vertex_buffer = {
         #[derive(Copy, Clone)]
@@ -75,19 +180,79 @@ fn main() -> Result<(), String> {
 
                 const float PI = 3.14159265358979323846264;
 
+                // Keep in sync with metadata::Projection
+                const int PROJECTION_EQUIRECTANGULAR = 0;
+                const int PROJECTION_CYLINDRICAL = 1;
+                const int PROJECTION_CUBEMAP = 2;
+
+                // Keep in sync with VIEW_* in main.rs
+                const int VIEW_RECTILINEAR = 0;
+                const int VIEW_LITTLE_PLANET = 1;
+
+                // Keep in sync with TONEMAP_* in main.rs
+                const int TONEMAP_NONE = 0;
+                const int TONEMAP_REINHARD = 1;
+                const int TONEMAP_FILMIC = 2;
+
                 uniform float window_aspect_ratio;
                 uniform float yaw;
                 uniform float pitch;
                 uniform float roll;
                 uniform float zoom;
+                uniform int view_mode;
 
+                uniform int projection;
                 uniform vec2 image_offset;
                 uniform vec2 image_fov;
 
+                // Sub-rectangle (in full-image UV space) covered by `tex`,
+                // when the panorama is split into tiles
+                uniform vec2 tile_offset;
+                uniform vec2 tile_size;
+
+                uniform float exposure;
+                uniform int tonemap;
+
                 uniform sampler2D tex;
                 uniform sampler2D bgtex;
                 in vec2 v_tex_coords;
                 out vec4 f_color;
+
+                // Map a direction to its position (in full-image UV space) on
+                // a horizontal-cross 6-face cubemap laid out as:
+                //         +-----+
+                //         | +Y  |
+                //   +-----+-----+-----+-----+
+                //   | -X  | +Z  | +X  | -Z  |
+                //   +-----+-----+-----+-----+
+                //         | -Y  |
+                //         +-----+
+                vec2 cubemap_pos(vec3 dir) {
+                    vec3 a = abs(dir);
+                    vec2 cell;
+                    vec2 uv;
+
+                    if (a.x >= a.y && a.x >= a.z) {
+                        uv = vec2(-dir.z / dir.x, -dir.y / a.x);
+                        cell = dir.x > 0.0 ? vec2(2, 1) : vec2(0, 1);
+                    } else if (a.y >= a.x && a.y >= a.z) {
+                        uv = vec2(dir.x / a.y, dir.z / dir.y);
+                        cell = dir.y > 0.0 ? vec2(1, 0) : vec2(1, 2);
+                    } else {
+                        uv = vec2(dir.x / dir.z, -dir.y / a.z);
+                        cell = dir.z > 0.0 ? vec2(1, 1) : vec2(3, 1);
+                    }
+
+                    uv = (uv + 1.0) * 0.5;
+
+                    // Textures are uploaded row-reversed (from_raw_rgba_reversed),
+                    // so v=1 is the top of the source file, matching the
+                    // equirectangular path where phi=+PI/2 (straight up) lands at
+                    // pos.y≈1. Mirror v here so +Y ends up in the top third (and
+                    // "up" is up within each face) instead of the bottom.
+                    return vec2((cell.x + uv.x) / 4.0, 1.0 - (cell.y + uv.y) / 3.0);
+                }
+
                 void main() {
                     float x = v_tex_coords.x ;
                     float y = v_tex_coords.y * window_aspect_ratio;
@@ -96,35 +261,95 @@ fn main() -> Result<(), String> {
                     float cosrot = cos(roll);
                     float rot_x = x * cosrot - y * sinrot;
                     float rot_y = x * sinrot + y * cosrot;
-                    float sintheta = sin(pitch);
-                    float costheta = cos(pitch);
-                    float a = zoom * costheta - rot_y * sintheta;
-                    float root = sqrt(rot_x * rot_x + a * a);
-                    float lambda = atan(rot_x / root, a / root) + yaw;
-                    float phi = atan((rot_y * costheta + zoom * sintheta) / root);
+
+                    float lambda;
+                    float phi;
+
+                    if (view_mode == VIEW_LITTLE_PLANET) {
+                        float r = sqrt(rot_x * rot_x + rot_y * rot_y);
+                        float az = atan(rot_y, rot_x);
+                        float c = 2.0 * atan(r * zoom);
+                        phi = -PI / 2.0 + c;
+                        lambda = az + yaw;
+                    } else {
+                        float sintheta = sin(pitch);
+                        float costheta = cos(pitch);
+                        float a = zoom * costheta - rot_y * sintheta;
+                        float root = sqrt(rot_x * rot_x + a * a);
+                        lambda = atan(rot_x / root, a / root) + yaw;
+                        phi = atan((rot_y * costheta + zoom * sintheta) / root);
+                    }
 
                     lambda = mod(lambda + PI, PI * 2.0) - PI;
 
-                    // Map texture to sphere
-                    vec2 coord = vec2(0.5 + lambda / PI / 2, 0.5 + phi / PI);
-                    vec2 pos = (coord - image_offset) / image_fov;
+                    vec3 color;
+
+                    vec2 pos;
+                    bool out_of_bounds = false;
 
-                    if (pos.y > 1 || pos.y < 0) {
-                        f_color = vec4(0, 0, 0, 1);
+                    if (projection == PROJECTION_CUBEMAP) {
+                        vec3 dir = vec3(cos(phi) * sin(lambda), sin(phi), cos(phi) * cos(lambda));
+                        pos = cubemap_pos(dir);
                     } else {
-                        f_color = texture(tex, pos);
+                        // Equirectangular and cylindrical panoramas both map
+                        // phi linearly to texture-v, and metadata::parse_gpano
+                        // places both with the same CroppedArea ratios. This
+                        // makes PROJECTION_CYLINDRICAL equirect-equivalent for
+                        // now rather than a true tan(phi) cylinder -- that's
+                        // intentional, not an oversight, pending a request for
+                        // genuine cylindrical (non-linear-in-phi) mapping.
+                        vec2 coord = vec2(0.5 + lambda / PI / 2, 0.5 + phi / PI);
+                        pos = (coord - image_offset) / image_fov;
+                        out_of_bounds = pos.y > 1 || pos.y < 0;
                     }
+
+                    if (out_of_bounds) {
+                        color = vec3(0, 0, 0);
+                    } else {
+                        // `pos` is in full-image UV space; `tex` only holds
+                        // the sub-rectangle owned by this tile (the whole
+                        // image for an untiled texture), so every projection
+                        // -- cubemap included -- goes through the same tile
+                        // lookup, letting other tiles' draw calls paint the
+                        // rest.
+                        vec2 tile_uv = (pos - tile_offset) / tile_size;
+                        if (tile_uv.x < 0 || tile_uv.x > 1 || tile_uv.y < 0 || tile_uv.y > 1) {
+                            discard;
+                        }
+                        color = texture(tex, tile_uv).rgb;
+                    }
+
+                    color *= exposure;
+
+                    if (tonemap == TONEMAP_REINHARD) {
+                        color = color / (1.0 + color);
+                    } else if (tonemap == TONEMAP_FILMIC) {
+                        // Cheap filmic curve (Jim Hejl / Richard Burgess-Dawson).
+                        // Its output already bakes in ~2.2 gamma, but f_color
+                        // is written to an SrgbTexture2d that re-encodes it on
+                        // store, so undo the curve's built-in gamma here to
+                        // avoid double-encoding.
+                        vec3 x = max(vec3(0.0), color - 0.004);
+                        color = (x * (6.2 * x + 0.5)) / (x * (6.2 * x + 1.7) + 0.06);
+                        color = pow(color, vec3(2.2));
+                    }
+
+                    f_color = vec4(color, 1.0);
                 }
             "
         }
     ).unwrap();
 
-    let mut yaw = 0.0f64;
+    let mut yaw = meta.initial_yaw as f64;
     let mut yaw_rate = 0.0;
-    let mut pitch = 0.0f64;
+    let mut pitch = meta.initial_pitch as f64;
     let mut pitch_rate = 0.0;
-    let mut zoom = 1.0f64;
+    let roll = meta.initial_roll as f64;
+    let mut zoom = meta.initial_zoom as f64;
     let mut zoom_rate = 1.0;
+    let mut view_mode = VIEW_RECTILINEAR;
+    let mut exposure = 1.0f64;
+    let mut tonemap = TONEMAP_NONE;
     let mut mouse_pos = (0.0f64, 0.0f64);
     let mut drag_state = None;
 
@@ -153,6 +378,35 @@ fn main() -> Result<(), String> {
                         (Some(PageUp),Released) => zoom_rate = 1.0,
                         (Some(PageDown),Pressed)  => zoom_rate = 1.01,
                         (Some(PageDown),Released) => zoom_rate = 1.0,
+                        (Some(L), Pressed) => {
+                            view_mode = if view_mode == VIEW_LITTLE_PLANET { VIEW_RECTILINEAR } else { VIEW_LITTLE_PLANET };
+                            display.gl_window().window().request_redraw();
+                        }
+                        (Some(LBracket), Pressed) => {
+                            exposure *= 0.9;
+                            display.gl_window().window().request_redraw();
+                        }
+                        (Some(RBracket), Pressed) => {
+                            exposure *= 1.1;
+                            display.gl_window().window().request_redraw();
+                        }
+                        (Some(T), Pressed) => {
+                            tonemap = (tonemap + 1) % TONEMAP_MODE_COUNT;
+                            display.gl_window().window().request_redraw();
+                        }
+                        (Some(S), Pressed) => {
+                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                            let path = format!("panobubble-export-{}.png", timestamp);
+                            let result = export_view(
+                                &display, &program, &vertex_buffer, &index_buffer, &mut pano_texture,
+                                &meta, yaw, pitch, roll, zoom, view_mode,
+                                exposure, tonemap,
+                                export_width, export_height, &path,
+                            );
+                            if let Err(e) = result {
+                                eprintln!("Export failed: {}", e);
+                            }
+                        }
                         _ => {}
                     }
                 }
@@ -204,30 +458,39 @@ fn main() -> Result<(), String> {
                 let mut target = display.draw();
                 let (width, height) = target.get_dimensions();
 
-                let uniforms = uniform! {
-                    window_aspect_ratio: height as f32 / width as f32,
-                    yaw: yaw as f32,
-                    pitch: pitch as f32,
-                    roll: 0.0f32,
-                    zoom: zoom as f32,
-                    image_offset: [ meta.crop_left, 1.0 - meta.crop_top - meta.height_ratio ],
-                    image_fov: [ meta.width_ratio, meta.height_ratio ],
-                    tex: opengl_texture.sampled()
-                        .wrap_function(SamplerWrapFunction::Clamp)
-                        .minify_filter(MinifySamplerFilter::Linear)
-                        .magnify_filter(MagnifySamplerFilter::Linear)
-                };
-
                 target.clear_color(0.0, 0.0, 0.0, 0.0);
-                target
-                    .draw(
-                        &vertex_buffer,
-                        &index_buffer,
-                        &program,
-                        &uniforms,
-                        &Default::default(),
-                    )
-                    .unwrap();
+
+                for tile in pano_texture.tiles_to_draw(&display, zoom, width) {
+                    let uniforms = uniform! {
+                        window_aspect_ratio: height as f32 / width as f32,
+                        yaw: yaw as f32,
+                        pitch: pitch as f32,
+                        roll: roll as f32,
+                        zoom: zoom as f32,
+                        view_mode: view_mode,
+                        exposure: exposure as f32,
+                        tonemap: tonemap,
+                        projection: meta.projection.as_gl_int(),
+                        image_offset: [ meta.crop_left, 1.0 - meta.crop_top - meta.height_ratio ],
+                        image_fov: [ meta.width_ratio, meta.height_ratio ],
+                        tile_offset: tile.offset,
+                        tile_size: tile.size,
+                        tex: tile.texture.sampled()
+                            .wrap_function(SamplerWrapFunction::Clamp)
+                            .minify_filter(MinifySamplerFilter::Linear)
+                            .magnify_filter(MagnifySamplerFilter::Linear)
+                    };
+
+                    target
+                        .draw(
+                            &vertex_buffer,
+                            &index_buffer,
+                            &program,
+                            &uniforms,
+                            &Default::default(),
+                        )
+                        .unwrap();
+                }
                 target.finish().unwrap();
             }
             _ => {}
@@ -1,32 +1,145 @@
+use std::f32::consts::PI;
 use std::str::FromStr;
 use memchr::memmem;
 use elementtree::Element;
+use exif::{In, Tag, Value};
+
+/// Layout of the source image on the unit sphere / cube
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Projection {
+    Equirectangular,
+    Cylindrical,
+    Cubemap,
+}
+
+impl Projection {
+    /// Value of the matching `PROJECTION_*` constant in the fragment shader
+    pub fn as_gl_int(&self) -> i32 {
+        match *self {
+            Projection::Equirectangular => 0,
+            Projection::Cylindrical => 1,
+            Projection::Cubemap => 2,
+        }
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct PanoMeta {
+    pub projection: Projection,
     pub width_ratio: f32,
     pub height_ratio: f32,
     pub crop_left: f32,
     pub crop_top: f32,
+
+    /// Initial viewer orientation and zoom, in radians / zoom factor
+    pub initial_yaw: f32,
+    pub initial_pitch: f32,
+    pub initial_roll: f32,
+    pub initial_zoom: f32,
 }
 
+/// Default initial view: looking at the horizon, facing forward, no zoom
+const DEFAULT_VIEW: (f32, f32, f32, f32) = (0.0, 0.0, 0.0, 1.0);
+
 /// Extract panorama metadata from the image
 ///
-/// Specifically, an equirectangular image may cover less than the full 360x180 FOV
-/// so we need to know where to place it on the sphere.
+/// Specifically, an equirectangular or cylindrical image may cover less than
+/// the full 360x180 FOV, so we need to know where to place it on the sphere.
+/// Cubemap images are assumed to cover the full sphere as a horizontal cross.
+///
+/// If there's no GPano metadata and the image isn't a 2:1 full sphere, fall
+/// back to the EXIF focal length to place it as a windowed rectilinear patch.
 pub fn parse(buf: &[u8], (w, h): (u32, u32)) -> Result<PanoMeta, String> {
     let gpano_result = find_xmp(buf).and_then(parse_gpano);
 
-    if gpano_result.is_err() && w/2 == h {
+    if gpano_result.is_ok() {
+        gpano_result
+    } else if w/2 == h {
         // Assume it's a full 360x180 degree image
+        let (initial_yaw, initial_pitch, initial_roll, initial_zoom) = DEFAULT_VIEW;
         Ok(PanoMeta {
+            projection: Projection::Equirectangular,
             width_ratio: 1.0,
             height_ratio: 1.0,
             crop_left: 0.0,
             crop_top: 0.0,
+            initial_yaw,
+            initial_pitch,
+            initial_roll,
+            initial_zoom,
         })
     } else {
-        gpano_result
+        parse_exif_fov(buf, (w, h)).or(gpano_result)
+    }
+}
+
+/// Place a rectilinear photo on the sphere using its EXIF focal length
+///
+/// Mirrors the fallback used by tools like Hugin when no panorama metadata
+/// is present: a 35mm-equivalent focal length (falling back to the raw lens
+/// focal length and sensor crop factor when the camera doesn't report the
+/// 35mm-equivalent directly) gives the horizontal FOV, and the image aspect
+/// ratio gives the vertical FOV, centered on the sphere.
+fn parse_exif_fov(buf: &[u8], (w, h): (u32, u32)) -> Result<PanoMeta, String> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(buf))
+        .map_err(|e| format!("Failed to parse EXIF: {:?}", e))?;
+
+    let focal35 = focal_length_35mm_equiv(&exif, w)
+        .ok_or_else(|| format!("No FocalLength in EXIF"))?;
+
+    let hfov = 2.0 * (36.0 / (2.0 * focal35)).atan();
+    // Derive vfov from the rectilinear lens projection, not by linearly
+    // scaling hfov by the aspect ratio (which understates it for wide lenses).
+    let vfov = 2.0 * ((hfov / 2.0).tan() * h as f32 / w as f32).atan();
+
+    let width_ratio = hfov / (2.0 * PI);
+    let height_ratio = vfov / PI;
+
+    let (initial_yaw, initial_pitch, initial_roll, initial_zoom) = DEFAULT_VIEW;
+    Ok(PanoMeta {
+        projection: Projection::Equirectangular,
+        width_ratio,
+        height_ratio,
+        crop_left: 0.5 - width_ratio / 2.0,
+        crop_top: 0.5 - height_ratio / 2.0,
+        initial_yaw,
+        initial_pitch,
+        initial_roll,
+        initial_zoom,
+    })
+}
+
+/// 35mm-equivalent focal length, preferring the EXIF tag that reports it
+/// directly and otherwise converting the raw lens focal length using the
+/// crop factor implied by the focal-plane resolution tags.
+fn focal_length_35mm_equiv(exif: &exif::Exif, width_px: u32) -> Option<f32> {
+    if let Some(focal35) = rational_field(exif, Tag::FocalLengthIn35mmFilm) {
+        return Some(focal35);
+    }
+
+    let focal_mm = rational_field(exif, Tag::FocalLength)?;
+    let res = rational_field(exif, Tag::FocalPlaneXResolution)?;
+    if res <= 0.0 {
+        return None;
+    }
+
+    // FocalPlaneResolutionUnit: 2 = inches (the EXIF default), 3 = centimeters
+    let unit_mm = match rational_field(exif, Tag::FocalPlaneResolutionUnit) {
+        Some(unit) if unit == 3.0 => 10.0,
+        _ => 25.4,
+    };
+    let sensor_width_mm = width_px as f32 / res * unit_mm;
+    let crop_factor = 36.0 / sensor_width_mm;
+
+    Some(focal_mm * crop_factor)
+}
+
+fn rational_field(exif: &exif::Exif, tag: Tag) -> Option<f32> {
+    match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Rational(v)) => v.get(0).map(|r| r.to_f64() as f32),
+        Some(Value::Short(v)) => v.get(0).map(|&v| v as f32),
+        _ => None,
     }
 }
 
@@ -60,17 +173,23 @@ fn parse_gpano(root: Element) -> Result<PanoMeta, String> {
     // while some (e.g. Hugin) put them in child tags, as specified by the link abouve.
     // We'll look in both places.
     fn field<T:FromStr>(e: &Element, tag: &str) -> Result<T, String> {
+        field_opt(e, tag).ok_or_else(|| format!("Missing GPano:{}", tag))
+    }
+
+    fn field_opt<T:FromStr>(e: &Element, tag: &str) -> Option<T> {
         e.find((GPANO, tag))
             .map(|c| c.text())
             .or_else(|| e.get_attr((GPANO, tag)))
             .and_then(|v| v.trim().parse::<T>().ok())
-            .ok_or_else(|| format!("Missing GPano:{}", tag))
     }
 
     let projection_type = field::<String>(elem, "ProjectionType")?;
-    if projection_type != "equirectangular" {
-        return Err(format!("Unsupported projection type {}", projection_type));
-    }
+    let projection = match projection_type.as_str() {
+        "equirectangular" => Projection::Equirectangular,
+        "cylindrical" => Projection::Cylindrical,
+        "cubemap" => Projection::Cubemap,
+        _ => return Err(format!("Unsupported projection type {}", projection_type)),
+    };
 
     let cropped_width   = field::<u32>(elem, "CroppedAreaImageWidthPixels")?;
     let cropped_height  = field::<u32>(elem, "CroppedAreaImageHeightPixels")?;
@@ -81,10 +200,30 @@ fn parse_gpano(root: Element) -> Result<PanoMeta, String> {
 
     println!("GPano: {} {} {} {} {} {} {}", projection_type, cropped_width, cropped_height, full_width, full_height, cropped_left, cropped_top);
 
+    // The initial heading falls back to the pose heading (the panorama's
+    // north) if no explicit initial view was authored.
+    let heading_deg = field_opt::<f32>(elem, "InitialViewHeadingDegrees")
+        .or_else(|| field_opt::<f32>(elem, "PoseHeadingDegrees"))
+        .unwrap_or(0.0);
+    let pitch_deg = field_opt::<f32>(elem, "InitialViewPitchDegrees").unwrap_or(0.0);
+    let roll_deg = field_opt::<f32>(elem, "InitialViewRollDegrees").unwrap_or(0.0);
+
+    // When no starting FOV was authored, match the no-metadata-at-all default
+    // (DEFAULT_VIEW) rather than silently picking a different initial zoom.
+    let (_, _, _, default_zoom) = DEFAULT_VIEW;
+    let initial_zoom = field_opt::<f32>(elem, "InitialHorizontalFovDegrees")
+        .map(|hfov_deg| 1.0 / (hfov_deg.to_radians() / 2.0).tan())
+        .unwrap_or(default_zoom);
+
     Ok(PanoMeta {
+        projection,
         width_ratio: cropped_width as f32 / full_width as f32,
         height_ratio: cropped_height as f32 / full_height as f32,
         crop_left: cropped_left as f32 / full_width as f32,
         crop_top: cropped_top as f32 / full_height as f32,
+        initial_yaw: heading_deg.to_radians(),
+        initial_pitch: pitch_deg.to_radians(),
+        initial_roll: roll_deg.to_radians(),
+        initial_zoom,
     })
 }
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, VecDeque};
+use glium::texture::{SrgbTexture2d, RawImage2d};
+
+/// Tiles are kept at this size (in texels) so a single tile comfortably fits
+/// within `GL_MAX_TEXTURE_SIZE` on the smallest GPUs we expect to run on.
+const TILE_SIZE: u32 = 2048;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct TileKey {
+    level: u32,
+    tile_x: u32,
+    tile_y: u32,
+}
+
+/// A tile ready to be bound and drawn: its texture plus the sub-rectangle of
+/// the full image (in UV space) that it covers.
+pub struct Tile<'a> {
+    pub texture: &'a SrgbTexture2d,
+    pub offset: [f32; 2],
+    pub size: [f32; 2],
+}
+
+/// Holds a panorama too large for a single GL texture as a mip pyramid of
+/// tiles, uploading only the tiles currently needed and evicting the
+/// least-recently-used ones once the cache is full -- analogous to Hugin's
+/// remapped-image cache, which keys its entries on image parameters and
+/// reuses them when unchanged.
+pub struct TiledTexture {
+    levels: Vec<image::RgbaImage>,
+    tile_size: u32,
+    cache: HashMap<TileKey, SrgbTexture2d>,
+    lru: VecDeque<TileKey>,
+    capacity: usize,
+}
+
+impl TiledTexture {
+    pub fn new(image: image::RgbaImage, max_texture_size: u32, capacity: usize) -> TiledTexture {
+        TiledTexture {
+            levels: build_mip_levels(image),
+            tile_size: max_texture_size.min(TILE_SIZE),
+            cache: HashMap::new(),
+            lru: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn level_count(&self) -> u32 {
+        self.levels.len() as u32
+    }
+
+    /// Pick the coarsest level that still has at least one texel per screen
+    /// pixel across the window at the current zoom, so we never upload more
+    /// detail than can be seen.
+    pub fn level_for_zoom(&self, zoom: f64, window_width: u32) -> u32 {
+        let (full_width, _) = self.levels[0].dimensions();
+        let visible_texels = full_width as f64 * (1.0 / zoom.max(0.01)).atan() / std::f64::consts::PI;
+        let ratio = (visible_texels / window_width.max(1) as f64).max(1.0);
+        let level = ratio.log2().floor().max(0.0) as u32;
+        level.min(self.level_count() - 1)
+    }
+
+    /// Fetch (uploading if necessary) every tile of `level`, along with the
+    /// UV sub-rectangle of the full image each one covers.
+    pub fn tiles_for_level(&mut self, display: &glium::Display, level: u32) -> Vec<Tile> {
+        let (lvl_width, lvl_height) = self.levels[level as usize].dimensions();
+        let tile_size = self.tile_size;
+        let cols = (lvl_width + tile_size - 1) / tile_size;
+        let rows = (lvl_height + tile_size - 1) / tile_size;
+        let needed = (cols * rows) as usize;
+
+        // A single level can need more tiles than `capacity` (e.g. a 32k-wide
+        // sphere at full res). Never evict below what the level being drawn
+        // this frame requires, or we'd evict tiles we just uploaded for it.
+        let capacity = self.capacity.max(needed);
+
+        let mut keys = Vec::with_capacity(needed);
+        for tile_y in 0..rows {
+            for tile_x in 0..cols {
+                let key = TileKey { level, tile_x, tile_y };
+                self.ensure_uploaded(display, key, capacity);
+                keys.push(key);
+            }
+        }
+
+        keys.into_iter().map(|key| {
+            let x0 = key.tile_x * tile_size;
+            let y0 = key.tile_y * tile_size;
+            let tw = tile_size.min(lvl_width - x0);
+            let th = tile_size.min(lvl_height - y0);
+            Tile {
+                texture: &self.cache[&key],
+                // The shader samples in bottom-up UV space (and each tile is
+                // itself row-reversed on upload), so the V origin of a tile's
+                // sub-rectangle is measured up from the bottom of the image.
+                offset: [x0 as f32 / lvl_width as f32, 1.0 - (y0 + th) as f32 / lvl_height as f32],
+                size: [tw as f32 / lvl_width as f32, th as f32 / lvl_height as f32],
+            }
+        }).collect()
+    }
+
+    fn ensure_uploaded(&mut self, display: &glium::Display, key: TileKey, capacity: usize) {
+        if !self.cache.contains_key(&key) {
+            while self.cache.len() >= capacity {
+                match self.lru.pop_front() {
+                    Some(evict) => { self.cache.remove(&evict); }
+                    None => break,
+                }
+            }
+
+            let img = &self.levels[key.level as usize];
+            let (w, h) = img.dimensions();
+            let x0 = key.tile_x * self.tile_size;
+            let y0 = key.tile_y * self.tile_size;
+            let tw = self.tile_size.min(w - x0);
+            let th = self.tile_size.min(h - y0);
+
+            let sub = image::imageops::crop_imm(img, x0, y0, tw, th).to_image();
+            let raw = RawImage2d::from_raw_rgba_reversed(&sub.into_raw(), (tw, th));
+            let tex = SrgbTexture2d::new(display, raw).expect("failed to upload panorama tile");
+            self.cache.insert(key, tex);
+        } else {
+            self.lru.retain(|k| *k != key);
+        }
+        self.lru.push_back(key);
+    }
+}
+
+fn build_mip_levels(image: image::RgbaImage) -> Vec<image::RgbaImage> {
+    let mut levels = vec![image];
+    loop {
+        let (w, h) = levels.last().unwrap().dimensions();
+        if w <= 1 && h <= 1 {
+            break;
+        }
+        let next_w = (w / 2).max(1);
+        let next_h = (h / 2).max(1);
+        let next = image::imageops::resize(levels.last().unwrap(), next_w, next_h, image::imageops::FilterType::Triangle);
+        levels.push(next);
+    }
+    levels
+}